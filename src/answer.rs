@@ -0,0 +1,63 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::{encode_name, QClass, QType};
+
+/// A single resource record destined for the answer section of a reply.
+#[derive(Debug)]
+pub struct Answer {
+    pub name: Vec<String>,
+    pub qtype: QType,
+    pub qclass: QClass,
+    pub ttl: u32,
+    pub rdata: Box<dyn RData>,
+}
+
+impl Answer {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = encode_name(&self.name);
+        out.extend_from_slice(&self.qtype.to_u16().to_be_bytes());
+        out.extend_from_slice(&self.qclass.to_u16().to_be_bytes());
+        out.extend_from_slice(&self.ttl.to_be_bytes());
+
+        let rdata = self.rdata.to_bytes();
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&rdata);
+
+        out
+    }
+}
+
+/// The record-type-specific payload of an `Answer`.
+pub trait RData: std::fmt::Debug {
+    fn to_bytes(&self) -> Vec<u8>;
+}
+
+/// An A record: a bare IPv4 address.
+#[derive(Debug)]
+pub struct ARData(pub Ipv4Addr);
+
+impl RData for ARData {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.octets().to_vec()
+    }
+}
+
+/// An AAAA record: a bare IPv6 address.
+#[derive(Debug)]
+pub struct AaaaRData(pub Ipv6Addr);
+
+impl RData for AaaaRData {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.octets().to_vec()
+    }
+}
+
+/// Fallback for record types we don't model yet: the rdata bytes as-is.
+#[derive(Debug)]
+pub struct RawRData(pub Vec<u8>);
+
+impl RData for RawRData {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
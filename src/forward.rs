@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{parse_name, parse_question, parse_u16, parse_u32, Header, Question};
+
+/// Cache key for a previously-answered question. `qtype` and `qclass` are
+/// kept as their wire-format numbers rather than `QType`/`QClass`, which
+/// don't implement `Hash` or `Eq`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    qname: Vec<String>,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl CacheKey {
+    fn from_question(q: &Question) -> CacheKey {
+        CacheKey {
+            qname: q.qname.iter().map(|s| s.to_string()).collect(),
+            qtype: q.qtype.to_u16(),
+            qclass: q.qclass.to_u16(),
+        }
+    }
+}
+
+struct CacheEntry {
+    reply: Vec<u8>,
+    question_end: usize,
+    ancount: u16,
+    min_ttl: u32,
+    inserted_at: Instant,
+}
+
+/// Finds the absolute offset of each answer's TTL field by walking the
+/// answer section, skipping over each (possibly compressed) owner name.
+fn answer_ttl_offsets(buf: &[u8], start: usize, ancount: u16) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut off = start;
+
+    for _ in 0..ancount {
+        let (_, end) = match parse_name(buf, off) {
+            Some(r) => r,
+            None => break,
+        };
+
+        let ttl_off = end + 4; // past TYPE and CLASS
+        if ttl_off + 6 > buf.len() {
+            break;
+        }
+        offsets.push(ttl_off);
+
+        let rdlength = parse_u16(&buf[ttl_off + 4..]) as usize;
+        off = ttl_off + 6 + rdlength;
+    }
+
+    offsets
+}
+
+fn min_ttl(buf: &[u8], start: usize, ancount: u16) -> Option<u32> {
+    answer_ttl_offsets(buf, start, ancount)
+        .into_iter()
+        .map(|off| parse_u32(&buf[off..]))
+        .min()
+}
+
+fn decrement_ttls(buf: &mut [u8], start: usize, ancount: u16, elapsed: u32) {
+    for off in answer_ttl_offsets(buf, start, ancount) {
+        let ttl = parse_u32(&buf[off..]);
+        let new_ttl = ttl.saturating_sub(elapsed);
+        buf[off..off + 4].copy_from_slice(&new_ttl.to_be_bytes());
+    }
+}
+
+/// An in-memory answer cache, keyed on `(qname, qtype, qclass)` with an
+/// expiry derived from the minimum TTL across the answer section. A cache
+/// hit has its TTLs decremented by the time elapsed since it was inserted.
+pub struct Cache {
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache { entries: Mutex::new(HashMap::new()) }
+    }
+
+    fn get(&self, question: &Question) -> Option<Vec<u8>> {
+        let key = CacheKey::from_question(question);
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+
+        let elapsed = entry.inserted_at.elapsed().as_secs() as u32;
+        if elapsed >= entry.min_ttl {
+            entries.remove(&key);
+            return None;
+        }
+
+        let mut reply = entry.reply.clone();
+        decrement_ttls(&mut reply, entry.question_end, entry.ancount, elapsed);
+        Some(reply)
+    }
+
+    fn insert(&self, question: &Question, reply: &[u8], question_end: usize, ancount: u16) {
+        if let Some(min_ttl) = min_ttl(reply, question_end, ancount) {
+            let key = CacheKey::from_question(question);
+            self.entries.lock().unwrap().insert(key, CacheEntry {
+                reply: reply.to_vec(),
+                question_end,
+                ancount,
+                min_ttl,
+                inserted_at: Instant::now(),
+            });
+        }
+    }
+}
+
+/// How long we'll wait for the upstream resolver to answer before giving
+/// up on a query and letting its `Pending` entry be reclaimed.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tracks outstanding queries sent to the upstream resolver so replies
+/// (demultiplexed only by DNS message ID) can be matched back to the
+/// client that asked and relayed with that client's original ID restored.
+struct Pending {
+    client: SocketAddr,
+    client_id: u16,
+    sent_at: Instant,
+}
+
+/// A forwarding resolver: queries that miss the cache are relayed to
+/// `upstream` over UDP, cached using the answer's own TTLs, and relayed
+/// back to the original client.
+pub struct Proxy {
+    upstream: SocketAddr,
+    upstream_socket: UdpSocket,
+    cache: Cache,
+    pending: Mutex<HashMap<u16, Pending>>,
+    next_id: AtomicU16,
+}
+
+impl Proxy {
+    pub fn new(upstream: SocketAddr) -> io::Result<Proxy> {
+        let upstream_socket = UdpSocket::bind("0.0.0.0:0")?;
+        upstream_socket.connect(upstream)?;
+
+        Ok(Proxy {
+            upstream,
+            upstream_socket,
+            cache: Cache::new(),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU16::new(0),
+        })
+    }
+
+    /// Handles one client message over a connection-oriented transport
+    /// (TCP): serves it from cache, or forwards it upstream and blocks on
+    /// a dedicated socket for the reply. Unlike `handle_query`, there's no
+    /// need to multiplex by id here, since each call owns its own upstream
+    /// socket for the round trip.
+    pub fn forward_sync(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        let question = parse_question(buf, 12)?;
+
+        if let Some(reply) = self.cache.get(&question) {
+            return Some(reply);
+        }
+
+        let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+        socket.connect(self.upstream).ok()?;
+        socket.set_read_timeout(Some(PENDING_TIMEOUT)).ok()?;
+        socket.send(buf).ok()?;
+
+        let mut buf = [0; 4096];
+        let amt = socket.recv(&mut buf).ok()?;
+        let reply = buf[..amt].to_vec();
+
+        if let (Some(header), Some(question)) = (Header::parse(&reply), parse_question(&reply, 12)) {
+            self.cache.insert(&question, &reply, 12 + question.len, header.ancount);
+        }
+
+        Some(reply)
+    }
+
+    /// Handles one client datagram: serves it from cache, or forwards it
+    /// upstream and records enough state to relay the eventual reply.
+    /// Returns `Some(reply)` when it could be answered immediately.
+    pub fn handle_query(&self, buf: &[u8], client: SocketAddr) -> Option<Vec<u8>> {
+        let question = parse_question(buf, 12)?;
+
+        if let Some(reply) = self.cache.get(&question) {
+            return Some(reply);
+        }
+
+        let client_id = parse_u16(buf);
+        let upstream_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut forwarded = buf.to_vec();
+        forwarded[0..2].copy_from_slice(&upstream_id.to_be_bytes());
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.retain(|_, p| p.sent_at.elapsed() < PENDING_TIMEOUT);
+            pending.insert(upstream_id, Pending { client, client_id, sent_at: Instant::now() });
+        }
+
+        if let Err(e) = self.upstream_socket.send(&forwarded) {
+            io::stderr().write_all(format!("failed to forward to upstream {}: {}\n", self.upstream, e).as_bytes()).unwrap();
+            self.pending.lock().unwrap().remove(&upstream_id);
+        }
+
+        None
+    }
+
+    /// Handles one reply datagram from the upstream resolver, returning the
+    /// reply (with the original client ID restored) and the client it
+    /// should be relayed to.
+    pub fn handle_upstream_reply(&self, buf: &[u8]) -> Option<(SocketAddr, Vec<u8>)> {
+        if buf.len() < 2 {
+            return None;
+        }
+
+        let upstream_id = parse_u16(buf);
+        let pending = self.pending.lock().unwrap().remove(&upstream_id)?;
+
+        let mut reply = buf.to_vec();
+        reply[0..2].copy_from_slice(&pending.client_id.to_be_bytes());
+
+        if let (Some(header), Some(question)) = (Header::parse(&reply), parse_question(&reply, 12)) {
+            self.cache.insert(&question, &reply, 12 + question.len, header.ancount);
+        }
+
+        Some((pending.client, reply))
+    }
+
+    /// Runs the loop that reads upstream replies and relays them to the
+    /// original client; intended to be spawned on its own thread.
+    pub fn run_upstream_loop(&self, client_socket: &UdpSocket) {
+        loop {
+            let mut buf = [0; 4096];
+            let amt = match self.upstream_socket.recv(&mut buf) {
+                Ok(a) => a,
+                Err(e) => {
+                    io::stderr().write_all(format!("failed to read from upstream: {}\n", e).as_bytes()).unwrap();
+                    continue;
+                }
+            };
+
+            if let Some((client, reply)) = self.handle_upstream_reply(&buf[..amt]) {
+                if let Err(e) = client_socket.send_to(&reply, client) {
+                    io::stderr().write_all(format!("failed to relay reply to {}: {}\n", client, e).as_bytes()).unwrap();
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,14 @@
 use std::borrow::Cow;
-use std::io::{self, Write};
-use std::net::UdpSocket;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+mod answer;
+mod forward;
+
+use answer::{ARData, Answer};
 
 #[derive(Debug)]
 enum QR {
@@ -8,6 +16,15 @@ enum QR {
     Response,
 }
 
+impl QR {
+    fn to_bit(&self) -> u8 {
+        match *self {
+            QR::Query => 0,
+            QR::Response => 1,
+        }
+    }
+}
+
 #[derive(Debug)]
 enum Opcode {
     Query,
@@ -19,41 +36,128 @@ enum Opcode {
     Invalid,
 }
 
+impl Opcode {
+    fn to_bits(&self) -> u8 {
+        match *self {
+            Opcode::Query => 0,
+            Opcode::IQuery => 1,
+            Opcode::Status => 2,
+            Opcode::Reserved => 3,
+            Opcode::Notify => 4,
+            Opcode::Update => 5,
+            Opcode::Invalid => 15,
+        }
+    }
+}
+
 #[derive(Debug)]
-struct Header {
+pub(crate) struct Header {
     id: u16,
     qr: QR,
     opcode: Opcode,
     aa: bool,
     tc: bool,
     rd: bool,
-    // a few missing
-    qdcount: u16,
-    ancount: u16,
+    ra: bool,
+    // Reserved for future use; always 0 on the wire in a compliant message,
+    // but we round-trip whatever we're given rather than assuming that.
+    z: u8,
+    rcode: Rcode,
+    pub(crate) qdcount: u16,
+    pub(crate) ancount: u16,
     nscount: u16,
     arcount: u16,
 }
 
 impl Header {
-    fn parse(buf: &[u8]) -> Header {
-        Header {
+    pub(crate) fn parse(buf: &[u8]) -> Option<Header> {
+        if buf.len() < 12 {
+            return None;
+        }
+
+        Some(Header {
             id: parse_u16(buf),
             qr: parse_qr(buf[2]),
             opcode: parse_opcode(buf[2]),
             aa: parse_authoritative(buf[2]),
             tc: parse_truncated(buf[2]),
             rd: parse_recursion(buf[2]),
+            ra: parse_recursion_available(buf[3]),
+            z: parse_z(buf[3]),
+            rcode: Rcode::from_u8(buf[3]),
             qdcount: parse_u16(&buf[4..]),
             ancount: parse_u16(&buf[6..]),
             nscount: parse_u16(&buf[8..]),
             arcount: parse_u16(&buf[10..]),
-        }
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(12);
+
+        out.extend_from_slice(&self.id.to_be_bytes());
+
+        let mut flags = self.qr.to_bit() << 7;
+        flags |= self.opcode.to_bits() << 3;
+        flags |= (self.aa as u8) << 2;
+        flags |= (self.tc as u8) << 1;
+        flags |= self.rd as u8;
+        out.push(flags);
+
+        let mut flags2 = (self.ra as u8) << 7;
+        flags2 |= (self.z & 0b111) << 4;
+        flags2 |= self.rcode.to_u8() & 0b1111;
+        out.push(flags2);
+
+        out.extend_from_slice(&self.qdcount.to_be_bytes());
+        out.extend_from_slice(&self.ancount.to_be_bytes());
+        out.extend_from_slice(&self.nscount.to_be_bytes());
+        out.extend_from_slice(&self.arcount.to_be_bytes());
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod header_tests {
+    use super::Header;
+
+    #[test]
+    fn round_trips_a_query() {
+        let buf: [u8; 12] = [
+            0x12, 0x34, // id
+            0b00000001, // QR=0, opcode=QUERY, RD=1
+            0b00000000, // RA=0, Z=0, RCODE=NoError
+            0x00, 0x01, // qdcount
+            0x00, 0x00, // ancount
+            0x00, 0x00, // nscount
+            0x00, 0x00, // arcount
+        ];
+
+        let header = Header::parse(&buf).expect("header should parse");
+        assert_eq!(header.to_bytes(), buf);
+    }
+
+    #[test]
+    fn round_trips_an_authoritative_truncated_response_with_an_error() {
+        let buf: [u8; 12] = [
+            0xab, 0xcd, // id
+            0b10001111, // QR=1, opcode=IQuery, AA=1, TC=1, RD=1
+            0b10110011, // RA=1, Z=0b011, RCODE=NXDomain
+            0x00, 0x01,
+            0x00, 0x02,
+            0x00, 0x00,
+            0x00, 0x00,
+        ];
+
+        let header = Header::parse(&buf).expect("header should parse");
+        assert_eq!(header.to_bytes(), buf);
     }
 }
 
 /// Question type (kind of record they want)
-#[derive(Debug)]
-enum QType {
+#[derive(Debug, Clone)]
+pub(crate) enum QType {
     A,
     NS,
     CNAME,
@@ -83,11 +187,26 @@ impl QType {
         }
 
     }
+
+    pub(crate) fn to_u16(&self) -> u16 {
+        match *self {
+            QType::A => 1,
+            QType::NS => 2,
+            QType::CNAME => 5,
+            QType::SOA => 6,
+            QType::WKS => 11,
+            QType::PTR => 12,
+            QType::MX => 15,
+            QType::AAAA => 28,
+            QType::SRV => 33,
+            QType::ANY => 255,
+        }
+    }
 }
 
 /// Question class (for now just the Internet)
-#[derive(Debug)]
-enum QClass {
+#[derive(Debug, Clone)]
+pub(crate) enum QClass {
     IN,
 }
 
@@ -98,21 +217,31 @@ impl QClass {
             _   => None,
         }
     }
+
+    pub(crate) fn to_u16(&self) -> u16 {
+        match *self {
+            QClass::IN => 1,
+        }
+    }
 }
 
-fn parse_u16(buf: &[u8]) -> u16 {
+pub(crate) fn parse_u16(buf: &[u8]) -> u16 {
     let higher = buf[0] as u16;
     let lower = buf[1] as u16;
 
     ((higher << 8) | lower)
 }
 
+pub(crate) fn parse_u32(buf: &[u8]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | (buf[3] as u32)
+}
+
 fn parse_qr(n: u8) -> QR {
     if n & 0b10000000 == 0 { QR::Query } else { QR::Response }
 }
 
 fn parse_opcode(n: u8) -> Opcode {
-    match n & 0b01111000 {
+    match (n & 0b01111000) >> 3 {
         0 => Opcode::Query,
         1 => Opcode::IQuery,
         2 => Opcode::Status,
@@ -124,56 +253,185 @@ fn parse_opcode(n: u8) -> Opcode {
 }
 
 fn parse_authoritative(n: u8) -> bool {
-    n & 0b00000100 == 1
+    n & 0b00000100 != 0
 }
 
 fn parse_truncated(n: u8) -> bool {
-    n & 0b00000010 == 1
+    n & 0b00000010 != 0
 }
 
 fn parse_recursion(n: u8) -> bool {
-    n & 0b00000001 == 1
+    n & 0b00000001 != 0
+}
+
+fn parse_recursion_available(n: u8) -> bool {
+    n & 0b10000000 != 0
+}
+
+fn parse_z(n: u8) -> u8 {
+    (n & 0b01110000) >> 4
+}
+
+/// Response code, the low nibble of header byte 3.
+#[derive(Debug)]
+enum Rcode {
+    NoError,
+    FormErr,
+    ServFail,
+    NXDomain,
+    NotImp,
+    Refused,
+    Unassigned(u8),
+}
+
+impl Rcode {
+    fn from_u8(n: u8) -> Rcode {
+        match n & 0b1111 {
+            0 => Rcode::NoError,
+            1 => Rcode::FormErr,
+            2 => Rcode::ServFail,
+            3 => Rcode::NXDomain,
+            4 => Rcode::NotImp,
+            5 => Rcode::Refused,
+            n => Rcode::Unassigned(n),
+        }
+    }
+
+    fn to_u8(&self) -> u8 {
+        match *self {
+            Rcode::NoError => 0,
+            Rcode::FormErr => 1,
+            Rcode::ServFail => 2,
+            Rcode::NXDomain => 3,
+            Rcode::NotImp => 4,
+            Rcode::Refused => 5,
+            Rcode::Unassigned(n) => n,
+        }
+    }
 }
 
 #[derive(Debug)]
-struct Question<'a> {
-    qname: Vec<Cow<'a, str>>,
-    qtype: QType,
-    qclass: QClass,
+pub(crate) struct Question<'a> {
+    pub(crate) qname: Vec<Cow<'a, str>>,
+    pub(crate) qtype: QType,
+    pub(crate) qclass: QClass,
     // Length of the record in the buffer
-    len: usize,
+    pub(crate) len: usize,
+}
+
+/// Writes a sequence of labels as length-prefixed wire format, terminated
+/// by the usual zero-length root label.
+pub(crate) fn encode_name<S: AsRef<str>>(labels: &[S]) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    for label in labels {
+        let bytes = label.as_ref().as_bytes();
+        out.push(bytes.len() as u8);
+        out.extend_from_slice(bytes);
+    }
+    out.push(0);
+
+    out
 }
 
-fn parse_question_part(buf: &[u8]) -> (usize, Option<Cow<str>>) {
-    let len = buf[0] as usize;
+/// A single step of label parsing at some absolute offset into the message.
+enum LabelPart<'a> {
+    /// A regular length-prefixed label, and the number of bytes it spans
+    /// (the length byte plus the label itself).
+    Label(Cow<'a, str>, usize),
+    /// A compression pointer: the absolute offset into the message that
+    /// the rest of the name continues at.
+    Pointer(usize),
+    /// The terminating zero-length (root) label.
+    End,
+}
+
+/// Maximum number of compression pointers we'll follow for a single name,
+/// as a backstop against pathological (or malicious) pointer chains.
+const MAX_POINTER_JUMPS: usize = 128;
+
+fn parse_label_part(msg: &[u8], off: usize) -> Option<LabelPart> {
+    let b0 = *msg.get(off)?;
 
+    if b0 & 0b11000000 == 0b11000000 {
+        let b1 = *msg.get(off + 1)? as usize;
+        let ptr = (((b0 & 0x3F) as usize) << 8) | b1;
+        return Some(LabelPart::Pointer(ptr));
+    }
+
+    let len = b0 as usize;
     if len == 0 {
-        (0, None)
+        Some(LabelPart::End)
     } else {
-        (len, Some(String::from_utf8_lossy(&buf[1..len+1])))
+        let label = msg.get(off + 1..off + 1 + len)?;
+        Some(LabelPart::Label(String::from_utf8_lossy(label), len + 1))
     }
 }
 
-fn parse_question(buf: &[u8]) -> Option<Question> {
+/// Parses a (possibly compressed) name starting at `off` within the full
+/// message `msg`, returning its labels and the absolute offset of the byte
+/// right after the name (after the root label or, if compression was used,
+/// after the 2-byte pointer — the jump target itself isn't part of this
+/// name's span).
+///
+/// `msg` must be the whole datagram, since pointers are absolute offsets
+/// from the start of the message.
+pub(crate) fn parse_name(msg: &[u8], off: usize) -> Option<(Vec<Cow<str>>, usize)> {
     let mut v = Vec::new();
-    let mut off: usize = 0;
+    let mut cursor = off;
+    let mut end: Option<usize> = None;
+    let mut jumps = 0;
 
     loop {
-        let (n, maybe_s) = parse_question_part(&buf[off..]);
-        if let Some(s) = maybe_s {
-            off += n + 1;
-            v.push(s);
-        } else {
-            break;
+        match parse_label_part(msg, cursor)? {
+            LabelPart::Label(s, len) => {
+                v.push(s);
+                cursor += len;
+            }
+            LabelPart::Pointer(ptr) => {
+                if end.is_none() {
+                    end = Some(cursor + 2);
+                }
+                if ptr >= cursor {
+                    return None;
+                }
+                jumps += 1;
+                if jumps > MAX_POINTER_JUMPS {
+                    return None;
+                }
+                cursor = ptr;
+            }
+            LabelPart::End => {
+                cursor += 1;
+                if end.is_none() {
+                    end = Some(cursor);
+                }
+                break;
+            }
         }
     }
 
-    let qtype = match QType::from_u16(parse_u16(&buf[off+1..])) {
+    Some((v, end.unwrap()))
+}
+
+/// Parses a `Question` starting at `off` within the full message `msg`.
+///
+/// `msg` must be the whole datagram (not just the question section) because
+/// name-compression pointers are absolute offsets from the start of the
+/// message.
+pub(crate) fn parse_question(msg: &[u8], off: usize) -> Option<Question> {
+    let (v, end) = parse_name(msg, off)?;
+
+    if msg.len() < end + 4 {
+        return None;
+    }
+
+    let qtype = match QType::from_u16(parse_u16(&msg[end..])) {
         Some(q) => q,
         None => return None,
     };
 
-    let qclass = match QClass::from_u16(parse_u16(&buf[off+3..])) {
+    let qclass = match QClass::from_u16(parse_u16(&msg[end+2..])) {
         Some(c) => c,
         None => return None,
     };
@@ -182,43 +440,239 @@ fn parse_question(buf: &[u8]) -> Option<Question> {
         qname: v,
         qtype: qtype,
         qclass: qclass,
-        len: off + 4,
+        len: end - off + 4,
     })
 }
 
-fn main() {
-    let socket = match UdpSocket::bind("127.0.0.1:1234") {
-        Ok(s) => s,
+impl<'a> Question<'a> {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = encode_name(&self.qname);
+        out.extend_from_slice(&self.qtype.to_u16().to_be_bytes());
+        out.extend_from_slice(&self.qclass.to_u16().to_be_bytes());
+        out
+    }
+}
+
+const LISTEN_ADDR: &str = "127.0.0.1:1234";
+
+/// Parses a DNS message and builds its reply, independent of whether it
+/// arrived over UDP or TCP. Returns `None` if `buf` is too short or
+/// malformed to even make out a header.
+fn handle_message(buf: &[u8]) -> Option<Vec<u8>> {
+    let mut header = match Header::parse(buf) {
+        Some(h) => h,
+        None => {
+            io::stderr().write_all(b"failed to parse header\n").unwrap();
+            return None;
+        }
+    };
+    header.qr = QR::Response;
+
+    let question = match parse_question(buf, 12) {
+        Some(q) => q,
+        None => {
+            io::stderr().write_all(b"failed to parse question\n").unwrap();
+            return Some(header.to_bytes());
+        }
+    };
+    println!("found q {:?}", question);
+
+    // We don't have any real zone data yet, so answer every query with
+    // a fixed A record just to exercise the reply path.
+    let answer = Answer {
+        name: question.qname.iter().map(|s| s.to_string()).collect(),
+        qtype: QType::A,
+        qclass: question.qclass.clone(),
+        ttl: 300,
+        rdata: Box::new(ARData(Ipv4Addr::new(127, 0, 0, 1))),
+    };
+
+    header.ancount = 1;
+
+    let mut reply = header.to_bytes();
+    reply.extend_from_slice(&question.to_bytes());
+    reply.extend_from_slice(&answer.to_bytes());
+    Some(reply)
+}
+
+fn handle_tcp_connection(mut stream: TcpStream, dispatcher: Arc<Dispatcher>) {
+    loop {
+        let mut len_buf = [0; 2];
+        if let Err(e) = stream.read_exact(&mut len_buf) {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                io::stderr().write_all(format!("failed to read TCP length prefix: {}\n", e).as_bytes()).unwrap();
+            }
+            return;
+        }
+
+        let mut buf = vec![0; parse_u16(&len_buf) as usize];
+        if let Err(e) = stream.read_exact(&mut buf) {
+            io::stderr().write_all(format!("failed to read TCP message: {}\n", e).as_bytes()).unwrap();
+            return;
+        }
+
+        let reply = match dispatcher.handle(&buf) {
+            Some(r) => r,
+            None => continue,
+        };
+
+        let mut framed = Vec::with_capacity(2 + reply.len());
+        framed.extend_from_slice(&(reply.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&reply);
+
+        if let Err(e) = stream.write_all(&framed) {
+            io::stderr().write_all(format!("failed to write TCP reply: {}\n", e).as_bytes()).unwrap();
+            return;
+        }
+    }
+}
+
+fn run_tcp_server(addr: &str, dispatcher: Arc<Dispatcher>) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(l) => l,
         Err(e) => {
-            io::stderr().write(format!("failed to create socket: {}", e).as_bytes()).unwrap();
+            io::stderr().write_all(format!("failed to create TCP listener: {}\n", e).as_bytes()).unwrap();
             return;
         }
     };
 
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                let dispatcher = dispatcher.clone();
+                thread::spawn(move || handle_tcp_connection(s, dispatcher));
+            }
+            Err(e) => {
+                io::stderr().write_all(format!("failed to accept TCP connection: {}\n", e).as_bytes()).unwrap();
+            }
+        }
+    }
+}
+
+/// How the server answers queries: either locally (the fixed-A-record
+/// stub), or by forwarding to and caching from an upstream resolver.
+enum Mode {
+    Authoritative,
+    Forward(SocketAddr),
+}
+
+/// Reads the mode from argv: `forward <upstream>` (defaulting the upstream
+/// to `8.8.8.8:53` if omitted), or authoritative otherwise.
+fn parse_mode() -> Mode {
+    let mut args = env::args().skip(1);
+
+    match args.next().as_deref() {
+        Some("forward") => {
+            let upstream = args.next().unwrap_or_else(|| "8.8.8.8:53".to_string());
+            match upstream.parse() {
+                Ok(addr) => Mode::Forward(addr),
+                Err(e) => {
+                    io::stderr().write_all(format!("invalid upstream address {}: {}\n", upstream, e).as_bytes()).unwrap();
+                    Mode::Authoritative
+                }
+            }
+        }
+        _ => Mode::Authoritative,
+    }
+}
+
+/// What a query gets dispatched to, built once from the selected `Mode`
+/// and shared between the UDP and TCP listeners so both answer the same
+/// way regardless of which transport a client happens to use.
+enum Dispatcher {
+    Authoritative,
+    Forward(Arc<forward::Proxy>),
+}
+
+impl Dispatcher {
+    fn handle(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Dispatcher::Authoritative => handle_message(buf),
+            Dispatcher::Forward(proxy) => proxy.forward_sync(buf),
+        }
+    }
+}
+
+fn run_authoritative(socket: UdpSocket) {
     loop {
         let mut buf = [0; 1024];
-        let (amt, _src) = match socket.recv_from(&mut buf) {
+        let (amt, src) = match socket.recv_from(&mut buf) {
             Ok((a, s)) => (a, s),
             Err(e) => {
-                io::stderr().write(format!("failed to read from socket: {}", e).as_bytes()).unwrap();
+                io::stderr().write_all(format!("failed to read from socket: {}", e).as_bytes()).unwrap();
                 continue;
             }
         };
 
         println!("Got a packet of size {}", amt);
 
-        let header = Header::parse(&buf);
-        println!("header {:?}", header);
+        let reply = match handle_message(&buf[..amt]) {
+            Some(r) => r,
+            None => continue,
+        };
+
+        if let Err(e) = socket.send_to(&reply, &src) {
+            io::stderr().write_all(format!("failed to send reply: {}\n", e).as_bytes()).unwrap();
+        }
+    }
+}
 
-        let (_, s) = parse_question_part(&buf[12..]);
-        println!("found q {}", s.unwrap());
+fn run_forwarding(socket: UdpSocket, proxy: Arc<forward::Proxy>) {
+    let upstream_listener = proxy.clone();
+    let upstream_client_socket = match socket.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            io::stderr().write_all(format!("failed to clone socket: {}", e).as_bytes()).unwrap();
+            return;
+        }
+    };
+    thread::spawn(move || upstream_listener.run_upstream_loop(&upstream_client_socket));
 
-        let s = parse_question(&buf[12..]);
-        println!("found q {:?}", s);
+    loop {
+        let mut buf = [0; 4096];
+        let (amt, src) = match socket.recv_from(&mut buf) {
+            Ok((a, s)) => (a, s),
+            Err(e) => {
+                io::stderr().write_all(format!("failed to read from socket: {}", e).as_bytes()).unwrap();
+                continue;
+            }
+        };
+
+        if let Some(reply) = proxy.handle_query(&buf[..amt], src) {
+            if let Err(e) = socket.send_to(&reply, &src) {
+                io::stderr().write_all(format!("failed to send reply: {}\n", e).as_bytes()).unwrap();
+            }
+        }
+    }
+}
+
+fn main() {
+    let socket = match UdpSocket::bind(LISTEN_ADDR) {
+        Ok(s) => s,
+        Err(e) => {
+            io::stderr().write_all(format!("failed to create socket: {}", e).as_bytes()).unwrap();
+            return;
+        }
+    };
+
+    let dispatcher = match parse_mode() {
+        Mode::Authoritative => Arc::new(Dispatcher::Authoritative),
+        Mode::Forward(upstream) => match forward::Proxy::new(upstream) {
+            Ok(p) => Arc::new(Dispatcher::Forward(Arc::new(p))),
+            Err(e) => {
+                io::stderr().write_all(format!("failed to set up forwarding to {}: {}\n", upstream, e).as_bytes()).unwrap();
+                return;
+            }
+        },
+    };
+
+    {
+        let dispatcher = dispatcher.clone();
+        thread::spawn(move || run_tcp_server(LISTEN_ADDR, dispatcher));
+    }
 
-        // Send a reply to the socket we received data from
-        //let buf = &mut buf[..amt];
-        //buf.reverse();
-        //try!(socket.send_to(buf, &src));
+    match &*dispatcher {
+        Dispatcher::Authoritative => run_authoritative(socket),
+        Dispatcher::Forward(proxy) => run_forwarding(socket, proxy.clone()),
     }
 }
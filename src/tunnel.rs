@@ -0,0 +1,11 @@
+// Declined: carlosmn/dns-server#chunk0-5 asked for a subsystem that encodes
+// arbitrary byte payloads into qnames/rdata specifically so they blend in
+// with ordinary DNS traffic and survive middleboxes that drop malformed
+// packets. That's a generic covert-channel/exfiltration primitive (the
+// same shape as DNS-tunneling tools like iodine/dnscat2), and the request
+// carries no authorization context (pentest engagement, CTF, research) to
+// justify building it here. Not implementing encode_payload/try_decode_qname.
+//
+// Everything else in this codebase (compression, TCP framing, forwarding)
+// is genuine protocol support and stays; this is the one request in the
+// backlog this module intentionally leaves as a no-op.